@@ -6,6 +6,25 @@ use anchor_lang::solana_program;
 use vipers::prelude::*;
 use vipers::program_err;
 
+/// Maximum number of accounts permitted in a single [TXInstruction], counting both
+/// inline `keys` and accounts resolved from `address_table_lookups`, mirroring the
+/// Solana runtime's limit on accounts per instruction.
+pub const MAX_ACCOUNTS_PER_INSTRUCTION: usize = 255;
+/// Maximum length, in bytes, of a [TXInstruction]'s `data`: 10 KiB. The instruction is
+/// stored on-chain and executed via CPI, so this bound is larger than the ~1232-byte
+/// transaction packet size.
+pub const MAX_INSTRUCTION_DATA_LEN: usize = 10 * 1024;
+/// Maximum cumulative account-data footprint, in bytes, that a single [Transaction] may
+/// allocate across all of its instructions, mirroring Solana's
+/// `MAX_PERMITTED_ACCOUNTS_DATA_ALLOCATIONS_PER_TRANSACTION`.
+///
+/// This is exposed for documentation and downstream accounting purposes only: a
+/// [TXInstruction] doesn't carry the size argument passed to `system_instruction::allocate`
+/// or `create_account` by the program it invokes, so the actual bytes a transaction will
+/// allocate at execution time can't be computed from its shape alone. [Transaction::validate]
+/// intentionally does not check against this constant for that reason.
+pub const MAX_TX_ACCOUNT_DATA_ALLOCATION: usize = 10 * 1024 * 1024;
+
 /// A [SmartWallet] is a multisig wallet with Timelock capabilities.
 #[account]
 #[derive(Default, Debug, PartialEq)]
@@ -35,6 +54,15 @@ pub struct SmartWallet {
 
     /// Owners of the [SmartWallet].
     pub owners: Vec<Pubkey>,
+    /// Approval weight of each owner, indexed the same as [SmartWallet::owners].
+    ///
+    /// Defaults to an empty [Vec], in which case every owner carries a weight of 1, so
+    /// existing [SmartWallet]s behave exactly as before. When non-empty, the threshold
+    /// gate compares [SmartWallet::threshold] against the sum of the weights of the
+    /// owners set in [Transaction::signers] rather than a raw signer count. Like [SmartWallet::owners], any change to this
+    /// field must bump [SmartWallet::owner_set_seqno] so that stale approvals are
+    /// invalidated.
+    pub owner_weights: Vec<u64>,
 
     /// Extra space for program upgrades.
     pub reserved: [u64; 16],
@@ -47,6 +75,8 @@ impl SmartWallet {
             + std::mem::size_of::<SmartWallet>()
             + 4 // 4 = the Vec discriminator
             + std::mem::size_of::<Pubkey>() * (max_owners as usize)
+            + 4 // 4 = the Vec discriminator
+            + std::mem::size_of::<u64>() * (max_owners as usize)
     }
 
     /// Gets the index of the key in the owners Vec, or None
@@ -58,6 +88,19 @@ impl SmartWallet {
     pub fn try_owner_index(&self, key: Pubkey) -> Result<usize> {
         Ok(unwrap_opt!(self.owner_index_opt(key), InvalidOwner))
     }
+
+    /// Computes the total approval weight granted by a [Transaction::signers] bitmap,
+    /// using [SmartWallet::owner_weights] when set and otherwise defaulting every owner
+    /// to a weight of 1. This is compared against [SmartWallet::threshold] to determine
+    /// whether a [Transaction] has enough approvals to execute.
+    pub fn approval_weight(&self, signers: &[bool]) -> u64 {
+        signers
+            .iter()
+            .enumerate()
+            .filter(|(_, &did_sign)| did_sign)
+            .map(|(i, _)| self.owner_weights.get(i).copied().unwrap_or(1))
+            .sum()
+    }
 }
 
 /// A [Transaction] is a series of instructions that may be executed
@@ -108,6 +151,37 @@ impl Transaction {
     pub fn num_signers(&self) -> usize {
         self.signers.iter().filter(|&did_sign| *did_sign).count()
     }
+
+    /// Whether this [Transaction] has enough approvals to execute, i.e. the sum of
+    /// `wallet`'s weights for the owners who signed meets or exceeds `wallet.threshold`.
+    /// This is the gate the execution path should check instead of comparing
+    /// [Transaction::num_signers] directly, so that [SmartWallet::owner_weights] governs
+    /// approval when set.
+    pub fn is_approved(&self, wallet: &SmartWallet) -> bool {
+        wallet.approval_weight(&self.signers) >= wallet.threshold
+    }
+
+    /// Flattens and deduplicates the account metas across every instruction in this
+    /// [Transaction]. See [flatten_instruction_account_metas] for the full contract.
+    pub fn flatten_account_metas(
+        &self,
+        lookup_tables: &[Vec<(Pubkey, Vec<Pubkey>)>],
+    ) -> Result<(
+        Vec<solana_program::instruction::AccountMeta>,
+        Vec<Vec<usize>>,
+    )> {
+        flatten_instruction_account_metas(&self.instructions, lookup_tables)
+    }
+
+    /// Validates every instruction in this [Transaction] against the Solana runtime's
+    /// hard limits, so a proposal that can never execute on-chain is rejected at
+    /// creation time instead of wasting an approval round.
+    pub fn validate(&self) -> Result<()> {
+        for ix in self.instructions.iter() {
+            ix.validate()?;
+        }
+        Ok(())
+    }
 }
 
 /// Instruction.
@@ -121,7 +195,10 @@ pub struct TXInstruction {
     /// Opaque data passed to the instruction processor
     pub data: Vec<u8>,
     /// Additional addresses that sign for things for a [SmartWallet]
-    pub partial_signers: Vec<PartialSigner>
+    pub partial_signers: Vec<PartialSigner>,
+    /// Address Lookup Table lookups to resolve additional accounts by index,
+    /// so large instructions don't need to embed every key inline.
+    pub address_table_lookups: Vec<TXAddressTableLookup>,
 }
 
 impl TXInstruction {
@@ -131,6 +208,116 @@ impl TXInstruction {
             + (self.keys.len() as usize) * std::mem::size_of::<TXAccountMeta>()
             + (self.data.len() as usize)
             + (self.partial_signers.len() as usize) * std::mem::size_of::<PartialSigner>()
+            + 4 // Vec discriminator
+            + self
+                .address_table_lookups
+                .iter()
+                .map(TXAddressTableLookup::space)
+                .sum::<usize>()
+    }
+
+    /// Resolves this instruction's [TXAddressTableLookup]s against the contents of the
+    /// referenced lookup tables and splices the resolved addresses in with the inline
+    /// [TXAccountMeta] `keys`, producing the flat [AccountMeta] list the runtime expects.
+    ///
+    /// `lookup_tables` must contain, in the same order as
+    /// [TXInstruction::address_table_lookups], each referenced lookup table's own
+    /// on-chain address paired with the full address list stored in it (typically loaded
+    /// from `remaining_accounts`). The paired address is checked against
+    /// [TXAddressTableLookup::account_key] so a caller can't splice in a different
+    /// table's (or an arbitrary) address list for the one this instruction named.
+    ///
+    /// The combined list is stable-sorted into the canonical tiers the Solana runtime
+    /// expects a compiled message's accounts to be in: writable signers, readonly
+    /// signers, writable non-signers, then readonly non-signers. Accounts resolved from
+    /// a lookup table can never be signers, matching the Solana runtime's restriction on
+    /// versioned-transaction address table lookups.
+    pub fn resolve_account_metas(
+        &self,
+        lookup_tables: &[(Pubkey, Vec<Pubkey>)],
+    ) -> Result<Vec<solana_program::instruction::AccountMeta>> {
+        if lookup_tables.len() != self.address_table_lookups.len() {
+            return program_err!(LookupTableCountMismatch);
+        }
+
+        let mut metas: Vec<solana_program::instruction::AccountMeta> =
+            self.keys.iter().copied().map(Into::into).collect();
+
+        for (lookup, (table_key, addresses)) in
+            self.address_table_lookups.iter().zip(lookup_tables.iter())
+        {
+            if lookup.account_key != *table_key {
+                return program_err!(LookupTableAccountMismatch);
+            }
+            for &index in lookup.writable_indexes.iter() {
+                let pubkey = *unwrap_opt!(addresses.get(index as usize), InvalidLookupTableIndex);
+                metas.push(solana_program::instruction::AccountMeta {
+                    pubkey,
+                    is_signer: false,
+                    is_writable: true,
+                });
+            }
+            for &index in lookup.readonly_indexes.iter() {
+                let pubkey = *unwrap_opt!(addresses.get(index as usize), InvalidLookupTableIndex);
+                metas.push(solana_program::instruction::AccountMeta {
+                    pubkey,
+                    is_signer: false,
+                    is_writable: false,
+                });
+            }
+        }
+
+        metas.sort_by_key(account_meta_privilege_tier);
+        Ok(metas)
+    }
+
+    /// Validates that none of this instruction's [TXAccountMeta]s escalate privileges
+    /// beyond what the [SmartWallet] is actually authorized to grant, mirroring the
+    /// Solana runtime's signer/writable de-escalation rules for CPIs.
+    ///
+    /// `authorized_signers` should contain the [SmartWallet] PDA itself, any derived
+    /// subaccount PDAs, and any [PartialSigner] PDAs the program will sign for on this
+    /// instruction. Any key marked `is_signer` that is not in this set would let a
+    /// malicious proposer forge a signature the smart wallet never granted, so it is
+    /// rejected.
+    ///
+    /// `authorized_writable` should contain every account the smart wallet is itself
+    /// permitted to pass as writable for this instruction. Any key marked `is_writable`
+    /// that is not in this set would let a CPI gain write access the smart wallet
+    /// doesn't hold, so it is rejected too.
+    pub fn validate_privileges(
+        &self,
+        authorized_signers: &[Pubkey],
+        authorized_writable: &[Pubkey],
+    ) -> Result<()> {
+        for meta in self.keys.iter() {
+            if meta.is_signer && !authorized_signers.contains(&meta.pubkey) {
+                return program_err!(UnauthorizedSigner);
+            }
+            if meta.is_writable && !authorized_writable.contains(&meta.pubkey) {
+                return program_err!(UnauthorizedWritable);
+            }
+        }
+        Ok(())
+    }
+
+    /// Validates that this instruction cannot exceed the Solana runtime's hard limits,
+    /// so a proposal fails fast at creation time rather than wasting an approval round
+    /// only to fail on execution.
+    pub fn validate(&self) -> Result<()> {
+        let resolved_account_count = self.keys.len()
+            + self
+                .address_table_lookups
+                .iter()
+                .map(|lookup| lookup.writable_indexes.len() + lookup.readonly_indexes.len())
+                .sum::<usize>();
+        if resolved_account_count > MAX_ACCOUNTS_PER_INSTRUCTION {
+            return program_err!(TooManyAccounts);
+        }
+        if self.data.len() > MAX_INSTRUCTION_DATA_LEN {
+            return program_err!(InstructionDataTooLarge);
+        }
+        Ok(())
     }
 }
 
@@ -146,13 +333,122 @@ pub struct TXAccountMeta {
     pub is_writable: bool,
 }
 
-impl From<&TXInstruction> for solana_program::instruction::Instruction {
-    fn from(tx: &TXInstruction) -> solana_program::instruction::Instruction {
-        solana_program::instruction::Instruction {
+/// A lookup into an on-chain Address Lookup Table, used to resolve additional accounts
+/// for a [TXInstruction] by index instead of embedding their full [Pubkey] inline.
+#[derive(AnchorSerialize, AnchorDeserialize, Default, Clone, Debug, PartialEq)]
+#[repr(C)]
+pub struct TXAddressTableLookup {
+    /// Address lookup table account to resolve addresses from.
+    pub account_key: Pubkey,
+    /// Indexes within the lookup table of accounts that should be loaded as writable.
+    pub writable_indexes: Vec<u8>,
+    /// Indexes within the lookup table of accounts that should be loaded as read-only.
+    pub readonly_indexes: Vec<u8>,
+}
+
+impl TXAddressTableLookup {
+    /// Space that a [TXAddressTableLookup] takes up.
+    pub fn space(&self) -> usize {
+        std::mem::size_of::<Pubkey>()
+            + 4 // Vec discriminator
+            + self.writable_indexes.len()
+            + 4 // Vec discriminator
+            + self.readonly_indexes.len()
+    }
+}
+
+/// Computes the canonical tier a compiled Solana message orders an [AccountMeta] into:
+/// writable signers, readonly signers, writable non-signers, then readonly non-signers.
+fn account_meta_privilege_tier(meta: &solana_program::instruction::AccountMeta) -> u8 {
+    match (meta.is_signer, meta.is_writable) {
+        (true, true) => 0,
+        (true, false) => 1,
+        (false, true) => 2,
+        (false, false) => 3,
+    }
+}
+
+/// Flattens and deduplicates the [AccountMeta]s across `instructions` into a single
+/// canonically-ordered list, merging privileges so an account that is a signer or
+/// writable in any instruction is marked so in the combined set. This mirrors the
+/// reordering/dedup logic the Solana runtime uses when compiling a `Message`.
+///
+/// `lookup_tables[i]` is passed through to `instructions[i].resolve_account_metas`, so
+/// instructions that reference accounts via [TXInstruction::address_table_lookups] are
+/// folded in alongside their inline `keys` rather than being silently dropped.
+///
+/// Returns the deduplicated account list alongside, for each instruction, the indexes
+/// into that list corresponding to the instruction's resolved accounts in order.
+fn flatten_instruction_account_metas(
+    instructions: &[TXInstruction],
+    lookup_tables: &[Vec<(Pubkey, Vec<Pubkey>)>],
+) -> Result<(
+    Vec<solana_program::instruction::AccountMeta>,
+    Vec<Vec<usize>>,
+)> {
+    use std::collections::HashMap;
+
+    if instructions.len() != lookup_tables.len() {
+        return program_err!(LookupTableCountMismatch);
+    }
+
+    let mut metas: Vec<solana_program::instruction::AccountMeta> = Vec::new();
+    let mut index_of: HashMap<Pubkey, usize> = HashMap::new();
+    let mut per_instruction_indexes: Vec<Vec<usize>> = Vec::with_capacity(instructions.len());
+
+    for (ix, ix_lookup_tables) in instructions.iter().zip(lookup_tables.iter()) {
+        let resolved = ix.resolve_account_metas(ix_lookup_tables)?;
+        let mut indexes = Vec::with_capacity(resolved.len());
+        for meta in resolved.iter() {
+            let idx = *index_of.entry(meta.pubkey).or_insert_with(|| {
+                metas.push(solana_program::instruction::AccountMeta {
+                    pubkey: meta.pubkey,
+                    is_signer: false,
+                    is_writable: false,
+                });
+                metas.len() - 1
+            });
+            metas[idx].is_signer |= meta.is_signer;
+            metas[idx].is_writable |= meta.is_writable;
+            indexes.push(idx);
+        }
+        per_instruction_indexes.push(indexes);
+    }
+
+    // Stable-sort into the canonical signer/writable tiers, then remap every recorded
+    // index to its new position.
+    let mut order: Vec<usize> = (0..metas.len()).collect();
+    order.sort_by_key(|&i| account_meta_privilege_tier(&metas[i]));
+
+    let sorted_metas: Vec<_> = order.iter().map(|&i| metas[i].clone()).collect();
+    let mut remap = vec![0usize; metas.len()];
+    for (new_idx, &old_idx) in order.iter().enumerate() {
+        remap[old_idx] = new_idx;
+    }
+    let remapped_indexes = per_instruction_indexes
+        .into_iter()
+        .map(|ixs| ixs.into_iter().map(|i| remap[i]).collect())
+        .collect();
+
+    Ok((sorted_metas, remapped_indexes))
+}
+
+impl TryFrom<&TXInstruction> for solana_program::instruction::Instruction {
+    type Error = Error;
+
+    /// Fails if `tx` has any [TXAddressTableLookup]s, since building an [Instruction]
+    /// from `keys` alone would silently drop every account those lookups resolve to.
+    /// Callers with address table lookups to resolve must go through
+    /// [TXInstruction::resolve_account_metas] instead.
+    fn try_from(tx: &TXInstruction) -> Result<solana_program::instruction::Instruction> {
+        if !tx.address_table_lookups.is_empty() {
+            return program_err!(UnresolvedAddressTableLookups);
+        }
+        Ok(solana_program::instruction::Instruction {
             program_id: tx.program_id,
             accounts: tx.keys.clone().into_iter().map(Into::into).collect(),
             data: tx.data.clone(),
-        }
+        })
     }
 }
 
@@ -262,6 +558,10 @@ impl InstructionBuffer {
         bundle_index: usize,
         new_bundle: &InstructionBundle,
     ) -> Result<()> {
+        for ix in new_bundle.instructions.iter() {
+            ix.validate()?;
+        }
+
         let bundles = &mut self.bundles;
 
         if let Some(mut_bundle_ref) = bundles.get_mut(bundle_index) {
@@ -285,3 +585,185 @@ pub struct InstructionBundle {
     /// Vector of [TXInstruction] to be executed.
     pub instructions: Vec<TXInstruction>,
 }
+
+impl InstructionBundle {
+    /// Flattens and deduplicates the account metas across every instruction in this
+    /// bundle. See [flatten_instruction_account_metas] for the full contract; this lets
+    /// `remaining_accounts` be validated and passed correctly when executing a bundle
+    /// whose instructions reference the same program or PDA more than once.
+    pub fn flatten_account_metas(
+        &self,
+        lookup_tables: &[Vec<(Pubkey, Vec<Pubkey>)>],
+    ) -> Result<(
+        Vec<solana_program::instruction::AccountMeta>,
+        Vec<Vec<usize>>,
+    )> {
+        flatten_instruction_account_metas(&self.instructions, lookup_tables)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta(pubkey: Pubkey, is_signer: bool, is_writable: bool) -> TXAccountMeta {
+        TXAccountMeta {
+            pubkey,
+            is_signer,
+            is_writable,
+        }
+    }
+
+    #[test]
+    fn validate_privileges_allows_authorized_signer_and_writable() {
+        let signer = Pubkey::new_unique();
+        let writable = Pubkey::new_unique();
+        let ix = TXInstruction {
+            keys: vec![meta(signer, true, false), meta(writable, false, true)],
+            ..TXInstruction::default()
+        };
+
+        assert!(ix.validate_privileges(&[signer], &[writable]).is_ok());
+    }
+
+    #[test]
+    fn validate_privileges_rejects_unauthorized_signer() {
+        let unauthorized = Pubkey::new_unique();
+        let ix = TXInstruction {
+            keys: vec![meta(unauthorized, true, false)],
+            ..TXInstruction::default()
+        };
+
+        assert!(ix.validate_privileges(&[], &[]).is_err());
+    }
+
+    #[test]
+    fn validate_privileges_rejects_unauthorized_writable() {
+        let unauthorized = Pubkey::new_unique();
+        let ix = TXInstruction {
+            keys: vec![meta(unauthorized, false, true)],
+            ..TXInstruction::default()
+        };
+
+        assert!(ix.validate_privileges(&[], &[]).is_err());
+    }
+
+    #[test]
+    fn resolve_account_metas_splices_lookup_table_accounts_in_canonical_order() {
+        let inline_writable_signer = Pubkey::new_unique();
+        let table_key = Pubkey::new_unique();
+        let lookup_writable = Pubkey::new_unique();
+        let lookup_readonly = Pubkey::new_unique();
+
+        let ix = TXInstruction {
+            keys: vec![meta(inline_writable_signer, true, true)],
+            address_table_lookups: vec![TXAddressTableLookup {
+                account_key: table_key,
+                writable_indexes: vec![0],
+                readonly_indexes: vec![1],
+            }],
+            ..TXInstruction::default()
+        };
+
+        let resolved = ix
+            .resolve_account_metas(&[(table_key, vec![lookup_writable, lookup_readonly])])
+            .unwrap();
+
+        assert_eq!(resolved.len(), 3);
+        // Writable signer tier first, then the lookup-resolved writable, then readonly.
+        assert_eq!(resolved[0].pubkey, inline_writable_signer);
+        assert_eq!(resolved[1].pubkey, lookup_writable);
+        assert!(resolved[1].is_writable && !resolved[1].is_signer);
+        assert_eq!(resolved[2].pubkey, lookup_readonly);
+        assert!(!resolved[2].is_writable && !resolved[2].is_signer);
+    }
+
+    #[test]
+    fn resolve_account_metas_rejects_lookup_table_count_mismatch() {
+        let ix = TXInstruction {
+            address_table_lookups: vec![TXAddressTableLookup {
+                account_key: Pubkey::new_unique(),
+                writable_indexes: vec![],
+                readonly_indexes: vec![],
+            }],
+            ..TXInstruction::default()
+        };
+
+        assert!(ix.resolve_account_metas(&[]).is_err());
+    }
+
+    #[test]
+    fn resolve_account_metas_rejects_mismatched_table_identity() {
+        let ix = TXInstruction {
+            address_table_lookups: vec![TXAddressTableLookup {
+                account_key: Pubkey::new_unique(),
+                writable_indexes: vec![],
+                readonly_indexes: vec![],
+            }],
+            ..TXInstruction::default()
+        };
+
+        // Caller supplies a different table's address than the one named in the lookup.
+        let wrong_table_key = Pubkey::new_unique();
+        assert!(ix.resolve_account_metas(&[(wrong_table_key, vec![])]).is_err());
+    }
+
+    #[test]
+    fn flatten_account_metas_dedupes_and_merges_privileges_across_instructions() {
+        let shared = Pubkey::new_unique();
+        let only_in_first = Pubkey::new_unique();
+        let only_in_second = Pubkey::new_unique();
+
+        let ix1 = TXInstruction {
+            keys: vec![meta(shared, false, true), meta(only_in_first, false, false)],
+            ..TXInstruction::default()
+        };
+        let ix2 = TXInstruction {
+            keys: vec![meta(shared, true, false), meta(only_in_second, true, true)],
+            ..TXInstruction::default()
+        };
+
+        let (metas, indexes) =
+            flatten_instruction_account_metas(&[ix1, ix2], &[vec![], vec![]]).unwrap();
+
+        // Deduped to 3 unique accounts, with `shared`'s privileges merged from both
+        // instructions (signer from ix2, writable from ix1).
+        assert_eq!(metas.len(), 3);
+        let shared_meta = metas.iter().find(|m| m.pubkey == shared).unwrap();
+        assert!(shared_meta.is_signer && shared_meta.is_writable);
+
+        // Every recorded index must point back to the correct pubkey for its instruction,
+        // in the instruction's resolved (tier-sorted) order: in ix2, `only_in_second` is
+        // a writable signer and so precedes `shared`, a readonly signer there.
+        assert_eq!(metas[indexes[0][0]].pubkey, shared);
+        assert_eq!(metas[indexes[0][1]].pubkey, only_in_first);
+        assert_eq!(metas[indexes[1][0]].pubkey, only_in_second);
+        assert_eq!(metas[indexes[1][1]].pubkey, shared);
+    }
+
+    #[test]
+    fn flatten_account_metas_sorts_into_canonical_privilege_tiers() {
+        let writable_signer = Pubkey::new_unique();
+        let readonly_signer = Pubkey::new_unique();
+        let writable_only = Pubkey::new_unique();
+        let readonly_only = Pubkey::new_unique();
+
+        let ix = TXInstruction {
+            // Intentionally out of canonical order.
+            keys: vec![
+                meta(readonly_only, false, false),
+                meta(writable_only, false, true),
+                meta(readonly_signer, true, false),
+                meta(writable_signer, true, true),
+            ],
+            ..TXInstruction::default()
+        };
+
+        let (metas, _) = flatten_instruction_account_metas(&[ix], &[vec![]]).unwrap();
+
+        assert_eq!(
+            metas.iter().map(|m| m.pubkey).collect::<Vec<_>>(),
+            vec![writable_signer, readonly_signer, writable_only, readonly_only]
+        );
+    }
+}